@@ -7,6 +7,10 @@ extern crate image;
 use image::ColorType;
 use image::ImageEncoder;
 use image::codecs::png::PngEncoder;
+extern crate rayon;
+use rayon::prelude::*;
+extern crate rand;
+use rand::Rng;
 
 
 fn parse_pair<T: FromStr>(s: &str, separator: char) -> Option<(T, T)> {
@@ -45,6 +49,19 @@ fn test_parse_complex() {
     assert_eq!(parse_complex("1.2,"), None);
 }
 
+fn find_flag<'a>(args: &'a [String], name: &str) -> Option<&'a str> {
+    let prefix = format!("--{}=", name);
+    args.iter().find_map(|arg| arg.strip_prefix(prefix.as_str()))
+}
+
+#[test]
+fn test_find_flag() {
+    let args = vec!["--threads=4".to_string(), "--fractal=julia".to_string()];
+    assert_eq!(find_flag(&args, "threads"), Some("4"));
+    assert_eq!(find_flag(&args, "fractal"), Some("julia"));
+    assert_eq!(find_flag(&args, "missing"), None);
+}
+
 fn pixel_to_point(bounds: (usize, usize), pixel: (usize, usize), upper_left: Complex<f64>, lower_right: Complex<f64>) -> Complex<f64> {
     let (width, height) = (lower_right.re - upper_left.re, upper_left.im - lower_right.im);
 
@@ -59,20 +76,125 @@ fn test_pixel_to_point() {
     assert_eq!(pixel_to_point((100,100), (25,75), Complex{re: -1.0, im: 1.0}, Complex{re: 1.0, im: -1.0}), Complex{re: -0.5, im: -0.5});
 }
 
-fn mandelbrot(c: Complex<f64>, limit :u32) -> Option<u32> {
-    let mut z = Complex{re: 0.0, im: 0.0};
+fn point_to_pixel(bounds: (usize, usize), point: Complex<f64>, upper_left: Complex<f64>, lower_right: Complex<f64>) -> Option<(usize, usize)> {
+    let (width, height) = (lower_right.re - upper_left.re, upper_left.im - lower_right.im);
+
+    let x = (point.re - upper_left.re) / width * bounds.0 as f64;
+    let y = (upper_left.im - point.im) / height * bounds.1 as f64;
+
+    if x < 0.0 || y < 0.0 || x >= bounds.0 as f64 || y >= bounds.1 as f64 {
+        return None;
+    }
+
+    Some((x as usize, y as usize))
+}
+
+#[test]
+fn test_point_to_pixel_is_inverse_of_pixel_to_point() {
+    let bounds = (100, 100);
+    let upper_left = Complex{re: -1.0, im: 1.0};
+    let lower_right = Complex{re: 1.0, im: -1.0};
+    let point = pixel_to_point(bounds, (25, 75), upper_left, lower_right);
+    assert_eq!(point_to_pixel(bounds, point, upper_left, lower_right), Some((25, 75)));
+    assert_eq!(point_to_pixel(bounds, Complex{re: 5.0, im: 5.0}, upper_left, lower_right), None);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum FractalKind {
+    Mandelbrot,
+    Multibrot3,
+    BurningShip,
+    Julia,
+}
+
+impl FromStr for FractalKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "mandelbrot" => Ok(FractalKind::Mandelbrot),
+            "multibrot3" => Ok(FractalKind::Multibrot3),
+            "burningship" => Ok(FractalKind::BurningShip),
+            "julia" => Ok(FractalKind::Julia),
+            _ => Err(format!("unknown fractal kind: {}", s))
+        }
+    }
+}
+
+#[test]
+fn test_fractal_kind_from_str() {
+    assert_eq!(FractalKind::from_str("mandelbrot"), Ok(FractalKind::Mandelbrot));
+    assert_eq!(FractalKind::from_str("BurningShip"), Ok(FractalKind::BurningShip));
+    assert_eq!(FractalKind::from_str("julia"), Ok(FractalKind::Julia));
+    assert!(FractalKind::from_str("nonsense").is_err());
+}
+
+fn fractal_step(kind: FractalKind, z: Complex<f64>, c: Complex<f64>) -> Complex<f64> {
+    match kind {
+        FractalKind::Multibrot3 => z * z * z + c,
+        FractalKind::BurningShip => {
+            let folded = Complex{re: z.re.abs(), im: z.im.abs()};
+            folded * folded + c
+        }
+        FractalKind::Mandelbrot | FractalKind::Julia => z * z + c
+    }
+}
+
+fn escape_time(kind: FractalKind, point: Complex<f64>, julia_c: Complex<f64>, limit: u32) -> Option<u32> {
+    let (mut z, c) = match kind {
+        FractalKind::Julia => (point, julia_c),
+        _ => (Complex{re: 0.0, im: 0.0}, point)
+    };
+
     for i in 0..limit {
-        z = z * z + c;
-        if z.norm_sqr() > 2.0 {
+        z = fractal_step(kind, z, c);
+
+        if z.norm_sqr() > 4.0 {
             return Some(i);
         }
     }
-    
+
     None
 }
 
+#[test]
+fn test_escape_time_mandelbrot() {
+    assert_eq!(escape_time(FractalKind::Mandelbrot, Complex{re: 0.0, im: 0.0}, Complex{re: 0.0, im: 0.0}, 255), None);
+    assert_eq!(escape_time(FractalKind::Mandelbrot, Complex{re: 5.0, im: 5.0}, Complex{re: 0.0, im: 0.0}, 255), Some(0));
+}
+
+const SMOOTH_BAILOUT_SQR: f64 = 256.0;
+const SMOOTH_EXTRA_ITERATIONS: u32 = 2;
+
+fn escape_time_smooth(kind: FractalKind, point: Complex<f64>, julia_c: Complex<f64>, limit: u32) -> Option<f64> {
+    let (mut z, c) = match kind {
+        FractalKind::Julia => (point, julia_c),
+        _ => (Complex{re: 0.0, im: 0.0}, point)
+    };
+
+    for i in 0..limit {
+        z = fractal_step(kind, z, c);
+
+        if z.norm_sqr() > SMOOTH_BAILOUT_SQR {
+            for _ in 0..SMOOTH_EXTRA_ITERATIONS {
+                z = fractal_step(kind, z, c);
+            }
+
+            return Some(i as f64 + 1.0 - (z.norm().ln().ln() / 2f64.ln()));
+        }
+    }
+
+    None
+}
+
+#[test]
+fn test_escape_time_smooth() {
+    assert_eq!(escape_time_smooth(FractalKind::Mandelbrot, Complex{re: 0.0, im: 0.0}, Complex{re: 0.0, im: 0.0}, 255), None);
+    assert!(escape_time_smooth(FractalKind::Mandelbrot, Complex{re: 5.0, im: 5.0}, Complex{re: 0.0, im: 0.0}, 255).is_some());
+}
+
 #[repr(C, packed)]
-#[derive(Clone)]
+#[derive(Clone, Copy)]
 struct Color {
     r: u8,
     g: u8,
@@ -99,17 +221,134 @@ fn color_from_value(value: u32) -> Color {
 }
 
 
-fn render(pixels: &mut [Color], bounds: (usize, usize), upper_left: Complex<f64>, lower_right: Complex<f64>) {
+fn lerp(a: u8, b: u8, t: f64) -> u8 {
+    (a as f64 + (b as f64 - a as f64) * t).round() as u8
+}
+
+#[test]
+fn test_lerp() {
+    assert_eq!(lerp(0, 100, 0.0), 0);
+    assert_eq!(lerp(0, 100, 1.0), 100);
+    assert_eq!(lerp(0, 100, 0.5), 50);
+}
+
+fn default_palette() -> Vec<Color> {
+    vec![
+        Color{r: 10, g: 10, b: 40},
+        Color{r: 40, g: 80, b: 160},
+        Color{r: 230, g: 230, b: 120},
+        Color{r: 230, g: 120, b: 40},
+        Color{r: 10, g: 10, b: 40},
+    ]
+}
+
+fn color_from_value_smooth(value: f64, palette: &[Color]) -> Color {
+    let t = value.max(0.0) % palette.len() as f64;
+    let index = t.floor() as usize;
+    let next = (index + 1) % palette.len();
+    let frac = t.fract();
+
+    Color {
+        r: lerp(palette[index].r, palette[next].r, frac),
+        g: lerp(palette[index].g, palette[next].g, frac),
+        b: lerp(palette[index].b, palette[next].b, frac)
+    }
+}
+
+fn buddhabrot(bounds: (usize, usize), upper_left: Complex<f64>, lower_right: Complex<f64>, samples: u32, limit: u32) -> Vec<u32> {
+    let mut histogram = vec![0u32; bounds.0 * bounds.1];
+    let mut trajectory = Vec::with_capacity(limit as usize);
+    let mut rng = rand::thread_rng();
+
+    for _ in 0..samples {
+        let c = Complex {
+            re: rng.gen_range(upper_left.re..lower_right.re),
+            im: rng.gen_range(lower_right.im..upper_left.im)
+        };
+
+        trajectory.clear();
+        let mut z = Complex{re: 0.0, im: 0.0};
+        let mut escaped = false;
+
+        for _ in 0..limit {
+            z = z * z + c;
+            trajectory.push(z);
+            if z.norm_sqr() > 4.0 {
+                escaped = true;
+                break;
+            }
+        }
+
+        if !escaped {
+            continue;
+        }
+
+        for point in &trajectory {
+            if let Some((x, y)) = point_to_pixel(bounds, *point, upper_left, lower_right) {
+                histogram[y * bounds.0 + x] += 1;
+            }
+        }
+    }
+
+    histogram
+}
+
+fn histogram_to_colors(histogram: &[u32]) -> Vec<Color> {
+    let max = histogram.iter().cloned().max().unwrap_or(0).max(1);
+
+    histogram.iter().map(|&count| {
+        let normalized = (count as f64 / max as f64).sqrt();
+        color_from_value((normalized * 255.0) as u32)
+    }).collect()
+}
+
+fn render(pixels: &mut [Color], bounds: (usize, usize), upper_left: Complex<f64>, lower_right: Complex<f64>, kind: FractalKind, julia_c: Complex<f64>, palette: Option<&[Color]>) {
     assert!(pixels.len() == bounds.0 * bounds.1);
 
     for y in 0..bounds.1 {
         for x in 0..bounds.0 {
             let point = pixel_to_point(bounds, (x, y), upper_left, lower_right);
-            pixels[y * bounds.0 + x] =
-                match mandelbrot(point, 255) {
+            pixels[y * bounds.0 + x] = match palette {
+                Some(palette) => match escape_time_smooth(kind, point, julia_c, 255) {
+                    None => Color{r: 0, g: 0, b: 0},
+                    Some(value) => color_from_value_smooth(value, palette)
+                },
+                None => match escape_time(kind, point, julia_c, 255) {
                     None => Color{r: 0, g: 0, b: 0},
                     Some(value) => color_from_value(value)
-                };
+                }
+            };
+        }
+    }
+}
+
+fn render_bands(pixels: &mut [Color], bounds: (usize, usize), upper_left: Complex<f64>, lower_right: Complex<f64>, kind: FractalKind, julia_c: Complex<f64>, palette: Option<&[Color]>, rows_per_band: usize) {
+    assert!(pixels.len() == bounds.0 * bounds.1);
+
+    let bands: Vec<(usize, &mut [Color])> =
+        pixels.chunks_mut(rows_per_band * bounds.0).enumerate().collect();
+
+    bands.into_par_iter().for_each(|(i, band)| {
+        let top = rows_per_band * i;
+        let height = band.len() / bounds.0;
+        let band_bounds = (bounds.0, height);
+        let band_upper_left = pixel_to_point(bounds, (0, top), upper_left, lower_right);
+        let band_lower_right = pixel_to_point(bounds, (bounds.0, top + height), upper_left, lower_right);
+
+        render(band, band_bounds, band_upper_left, band_lower_right, kind, julia_c, palette);
+    });
+}
+
+fn render_values(values: &mut [u8], bounds: (usize, usize), upper_left: Complex<f64>, lower_right: Complex<f64>, kind: FractalKind, julia_c: Complex<f64>) {
+    assert!(values.len() == bounds.0 * bounds.1);
+
+    for y in 0..bounds.1 {
+        for x in 0..bounds.0 {
+            let point = pixel_to_point(bounds, (x, y), upper_left, lower_right);
+            values[y * bounds.0 + x] = match escape_time(kind, point, julia_c, 255) {
+                None => 0,
+                Some(value) => value as u8
+            };
         }
     }
 }
@@ -118,17 +357,115 @@ fn write_png(filename: &str, pixels: &[Color], bounds: (usize, usize)) {
     let file = File::create(filename).unwrap();
     let encoder = PngEncoder::new(file);
 
-    let bytes = colors_to_u8s(&pixels); 
+    let bytes = colors_to_u8s(&pixels);
 
     encoder.write_image(&bytes, bounds.0 as u32, bounds.1 as u32, ColorType::Rgb8);
 }
 
-fn main() {
-    let args: Vec<String> = std::env::args().collect();
+fn qoi_hash(color: Color) -> usize {
+    (color.r as usize * 3 + color.g as usize * 5 + color.b as usize * 7 + 255 * 11) % 64
+}
+
+#[test]
+fn test_qoi_hash_in_range() {
+    for color in [Color{r: 0, g: 0, b: 0}, Color{r: 255, g: 255, b: 255}, Color{r: 12, g: 200, b: 77}] {
+        assert!(qoi_hash(color) < 64);
+    }
+}
+
+fn write_qoi(filename: &str, pixels: &[Color], bounds: (usize, usize)) {
+    let mut out = Vec::with_capacity(14 + pixels.len() * 4 + 8);
+
+    out.extend_from_slice(b"qoif");
+    out.extend_from_slice(&(bounds.0 as u32).to_be_bytes());
+    out.extend_from_slice(&(bounds.1 as u32).to_be_bytes());
+    out.push(3); // channels
+    out.push(0); // sRGB colorspace
+
+    let mut index = [Color{r: 0, g: 0, b: 0}; 64];
+    let mut prev = Color{r: 0, g: 0, b: 0};
+    let mut run = 0u8;
+
+    for (i, &pixel) in pixels.iter().enumerate() {
+        if pixel.r == prev.r && pixel.g == prev.g && pixel.b == prev.b {
+            run += 1;
+            if run == 62 || i == pixels.len() - 1 {
+                out.push(0b1100_0000 | (run - 1));
+                run = 0;
+            }
+            continue;
+        }
+
+        if run > 0 {
+            out.push(0b1100_0000 | (run - 1));
+            run = 0;
+        }
+
+        let hash = qoi_hash(pixel);
+        if index[hash].r == pixel.r && index[hash].g == pixel.g && index[hash].b == pixel.b {
+            out.push(hash as u8);
+        } else {
+            index[hash] = pixel;
+
+            let dr = pixel.r.wrapping_sub(prev.r) as i8;
+            let dg = pixel.g.wrapping_sub(prev.g) as i8;
+            let db = pixel.b.wrapping_sub(prev.b) as i8;
+
+            if (-2..=1).contains(&dr) && (-2..=1).contains(&dg) && (-2..=1).contains(&db) {
+                out.push(0b0100_0000 | (((dr + 2) as u8) << 4) | (((dg + 2) as u8) << 2) | (db + 2) as u8);
+            } else {
+                let dr_dg = dr.wrapping_sub(dg);
+                let db_dg = db.wrapping_sub(dg);
+
+                if (-32..=31).contains(&dg) && (-8..=7).contains(&dr_dg) && (-8..=7).contains(&db_dg) {
+                    out.push(0b1000_0000 | (dg + 32) as u8);
+                    out.push((((dr_dg + 8) as u8) << 4) | (db_dg + 8) as u8);
+                } else {
+                    out.push(0b1111_1110);
+                    out.push(pixel.r);
+                    out.push(pixel.g);
+                    out.push(pixel.b);
+                }
+            }
+        }
+
+        prev = pixel;
+    }
 
-    if args.len() != 5 {
-        writeln!(std::io::stderr(), "Usage: {} <png file> <image size> <upper left> <lower right>", args[0]).unwrap();
-        writeln!(std::io::stderr(), "Example: {} mandel.png 800x600 -1.20,0.35 -1,0.20", args[0]).unwrap();
+    out.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 1]);
+
+    let mut file = File::create(filename).unwrap();
+    file.write_all(&out).unwrap();
+}
+
+fn write_ppm(filename: &str, pixels: &[Color], bounds: (usize, usize)) {
+    let mut file = File::create(filename).unwrap();
+    write!(file, "P6\n{} {}\n255\n", bounds.0, bounds.1).unwrap();
+    file.write_all(colors_to_u8s(pixels)).unwrap();
+}
+
+fn write_pgm(filename: &str, values: &[u8], bounds: (usize, usize)) {
+    let mut file = File::create(filename).unwrap();
+    write!(file, "P5\n{} {}\n255\n", bounds.0, bounds.1).unwrap();
+    file.write_all(values).unwrap();
+}
+
+fn write_image(filename: &str, pixels: &[Color], bounds: (usize, usize)) {
+    let lowercase = filename.to_lowercase();
+
+    if lowercase.ends_with(".qoi") {
+        write_qoi(filename, pixels, bounds);
+    } else if lowercase.ends_with(".ppm") {
+        write_ppm(filename, pixels, bounds);
+    } else {
+        write_png(filename, pixels, bounds);
+    }
+}
+
+fn run_mandelbrot(args: &[String]) {
+    if args.len() < 5 {
+        writeln!(std::io::stderr(), "Usage: {} <output file: .png|.qoi|.ppm|.pgm> <image size> <upper left> <lower right> [--threads=N] [--fractal=kind] [--julia-c=re,im] [--smooth]", args[0]).unwrap();
+        writeln!(std::io::stderr(), "Example: {} mandel.png 800x600 -1.20,0.35 -1,0.20 --threads=8 --fractal=burningship --smooth", args[0]).unwrap();
         std::process::exit(1);
     }
 
@@ -136,8 +473,68 @@ fn main() {
     let upper_left = parse_complex(&args[3]).expect("failed to parse upper left");
     let lower_right = parse_complex(&args[4]).expect("failed to parse lower right");
 
+    let kind = match find_flag(args, "fractal") {
+        Some(s) => FractalKind::from_str(s).expect("unknown --fractal kind"),
+        None => FractalKind::Mandelbrot
+    };
+    let julia_c = match find_flag(args, "julia-c") {
+        Some(s) => parse_complex(s).expect("failed to parse --julia-c"),
+        None => Complex{re: 0.0, im: 0.0}
+    };
+
+    if args[1].to_lowercase().ends_with(".pgm") {
+        let mut values = vec![0u8; bounds.0 * bounds.1];
+        render_values(&mut values, bounds, upper_left, lower_right, kind, julia_c);
+        write_pgm(&args[1], &values, bounds);
+        return;
+    }
+
+    let palette = if args.iter().any(|a| a == "--smooth") {
+        Some(default_palette())
+    } else {
+        None
+    };
+    let palette = palette.as_deref();
+
     let mut pixels = vec![Color{r: 0, g: 0, b: 0}; bounds.0 * bounds.1];
 
-    render(&mut pixels, bounds, upper_left, lower_right);
-    write_png(&args[1], &pixels, bounds);
+    match find_flag(args, "threads").map(|s| s.parse::<usize>()) {
+        Some(Ok(threads)) if threads > 1 => {
+            let rows_per_band = (bounds.1 + threads - 1) / threads;
+            render_bands(&mut pixels, bounds, upper_left, lower_right, kind, julia_c, palette, rows_per_band);
+        }
+        _ => render(&mut pixels, bounds, upper_left, lower_right, kind, julia_c, palette),
+    }
+
+    write_image(&args[1], &pixels, bounds);
+}
+
+fn run_buddhabrot(args: &[String]) {
+    if args.len() < 5 {
+        writeln!(std::io::stderr(), "Usage: <bin> buddhabrot <png file> <image size> <upper left> <lower right> [--samples=N] [--limit=N]").unwrap();
+        writeln!(std::io::stderr(), "Example: <bin> buddhabrot buddha.png 800x600 -2.0,1.2 1.0,-1.2 --samples=2000000 --limit=500").unwrap();
+        std::process::exit(1);
+    }
+
+    let bounds = parse_pair::<usize>(&args[2], 'x').expect("failed to parse image size");
+    let upper_left = parse_complex(&args[3]).expect("failed to parse upper left");
+    let lower_right = parse_complex(&args[4]).expect("failed to parse lower right");
+
+    let samples = find_flag(args, "samples").map(|s| s.parse::<u32>().expect("invalid --samples")).unwrap_or(1_000_000);
+    let limit = find_flag(args, "limit").map(|s| s.parse::<u32>().expect("invalid --limit")).unwrap_or(500);
+
+    let histogram = buddhabrot(bounds, upper_left, lower_right, samples, limit);
+    let pixels = histogram_to_colors(&histogram);
+
+    write_image(&args[1], &pixels, bounds);
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+
+    if args.len() > 1 && args[1] == "buddhabrot" {
+        run_buddhabrot(&args[1..]);
+    } else {
+        run_mandelbrot(&args);
+    }
 }